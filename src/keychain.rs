@@ -0,0 +1,222 @@
+//! Descriptor-wallet tracking with gap-limit script revelation.
+//!
+//! This is an alternative to [`SpkTracker`](crate::SpkTracker) for users who want to track full
+//! miniscript [`Descriptor`]s (ranged xpubs) instead of single bare keys: [`KeychainTracker`]
+//! wraps a [`KeychainTxOutIndex`] rather than a flat [`SpkTxOutIndex`](bdk_chain::spk_txout::SpkTxOutIndex).
+
+use std::sync::Arc;
+
+use bdk_bitcoind_rpc::{BlockEvent, MempoolEvent};
+use bdk_chain::{
+    CanonicalizationParams, CheckPoint, ConfirmationBlockTime, FullTxOut, Indexed, Merge, TxUpdate,
+    bitcoin::{Block, BlockHash, Network, ScriptBuf, Transaction, Txid},
+    keychain_txout::KeychainTxOutIndex,
+    miniscript::{Descriptor, DescriptorPublicKey},
+    spk_client::{FullScanRequest, FullScanRequestBuilder, SyncRequest, SyncRequestBuilder},
+};
+
+use crate::tracker::Tracker;
+
+/// Persistable changes for a [`KeychainTracker`].
+pub type KeychainChangeSet = crate::tracker::ChangeSet<bdk_chain::keychain_txout::ChangeSet>;
+
+/// Tracks one or more ranged descriptors, each identified by a keychain `K`, revealing spks on
+/// demand up to a gap limit.
+pub struct KeychainTracker<K: Ord + Clone + core::fmt::Debug> {
+    inner: Tracker<KeychainTxOutIndex<K>>,
+}
+
+impl<K: Ord + Clone + core::fmt::Debug> KeychainTracker<K> {
+    pub fn new(network: Network, genesis_hash: BlockHash) -> Self {
+        Self {
+            inner: Tracker::new(network, genesis_hash),
+        }
+    }
+
+    pub fn from_changeset(changeset: KeychainChangeSet) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: Tracker::from_changeset(changeset)?,
+        })
+    }
+
+    /// Take from the staged changes.
+    ///
+    /// For persistence.
+    pub fn take_stage(&mut self) -> KeychainChangeSet {
+        self.inner.take_stage()
+    }
+
+    /// Reindex.
+    ///
+    /// Incase a descriptor was added after a relevant transaction was already synced.
+    pub fn reindex(&mut self) -> bool {
+        self.inner.reindex()
+    }
+}
+
+/// Methods for managing descriptors, revealed spks, and UTXOs.
+impl<K: Ord + Clone + core::fmt::Debug> KeychainTracker<K> {
+    /// Track a new keychain's descriptor.
+    ///
+    /// Remember to call [`reindex`](KeychainTracker::reindex) if the descriptor is added after a
+    /// relevant transaction is already seen by the `KeychainTracker`.
+    pub fn insert_descriptor(
+        &mut self,
+        keychain: K,
+        descriptor: Descriptor<DescriptorPublicKey>,
+    ) -> anyhow::Result<bool> {
+        let (inserted, changeset) = self
+            .inner
+            .graph
+            .index
+            .insert_descriptor(keychain, descriptor)?;
+        self.inner.stage.merge(changeset.into());
+        Ok(inserted)
+    }
+
+    /// Reveal and return the next not-yet-revealed spk of `keychain`, whether or not the
+    /// previously revealed spks have been used.
+    pub fn reveal_next_spk(&mut self, keychain: &K) -> Option<Indexed<ScriptBuf>> {
+        let (indexed_spk, changeset) = self.inner.graph.index.reveal_next_spk(keychain)?;
+        self.inner.stage.merge(changeset.into());
+        Some(indexed_spk)
+    }
+
+    /// Reveal every spk of `keychain` up to and including `target_index`.
+    pub fn reveal_to_target(&mut self, keychain: &K, target_index: u32) -> Vec<Indexed<ScriptBuf>> {
+        let Some((revealed, changeset)) = self
+            .inner
+            .graph
+            .index
+            .reveal_to_target(keychain, target_index)
+        else {
+            return Vec::new();
+        };
+        self.inner.stage.merge(changeset.into());
+        revealed.collect()
+    }
+
+    /// Canonical UTXOs, keyed by the keychain and derivation index that produced their spk.
+    pub fn utxos(&self) -> impl Iterator<Item = ((K, u32), FullTxOut<ConfirmationBlockTime>)> {
+        self.inner.graph.filter_chain_unspents(
+            &self.inner.chain,
+            self.inner.chain.tip().block_id(),
+            CanonicalizationParams::default(),
+            self.inner.graph.index.outpoints().clone(),
+        )
+    }
+}
+
+/// Methods for syncing with `bdk_electrum`/`bdk_esplora`.
+impl<K: Ord + Clone + core::fmt::Debug> KeychainTracker<K> {
+    /// Start a [`SyncRequest`] for every already-revealed spk.
+    ///
+    /// The request starts from [`KeychainTracker::tip`] and is pre-populated with the txs already
+    /// in `self.graph`, so the client only needs to fetch what it doesn't already have.
+    pub fn start_sync(&self) -> SyncRequestBuilder<(K, u32)> {
+        SyncRequest::builder()
+            .chain_tip(self.inner.chain.tip())
+            .spks_with_indexes(self.inner.graph.index.revealed_spks(..))
+            .cache_graph_txs(&self.inner.graph)
+    }
+
+    /// Start a [`FullScanRequest`] with an unbounded spk iterator per keychain, so the scanning
+    /// client can apply its own gap-limit stop-gap and reveal new spks as it goes.
+    pub fn start_full_scan(&self) -> FullScanRequestBuilder<K> {
+        FullScanRequest::builder()
+            .chain_tip(self.inner.chain.tip())
+            .spks_for_all_keychains(self.inner.graph.index.all_unbounded_spk_iters())
+            .cache_graph_txs(&self.inner.graph)
+    }
+
+    /// Fold a [`TxUpdate`] and new `tip` (as returned by `bdk_electrum`/`bdk_esplora`) back into
+    /// `self.graph`/`self.chain`, staging the resulting changeset.
+    pub fn apply_update(
+        &mut self,
+        update: TxUpdate<ConfirmationBlockTime>,
+        tip: CheckPoint,
+    ) -> anyhow::Result<()> {
+        self.inner.apply_update(update, tip)
+    }
+}
+
+/// Methods for syncing with `bdk_bitcoind_rpc`.
+impl<K: Ord + Clone + core::fmt::Debug> KeychainTracker<K> {
+    pub fn tip(&self) -> CheckPoint {
+        self.inner.tip()
+    }
+
+    pub fn expected_mempool_txs(&self) -> impl Iterator<Item = Arc<Transaction>> {
+        self.inner.expected_mempool_txs()
+    }
+
+    pub fn consume_block_event(&mut self, event: BlockEvent<Block>) -> anyhow::Result<()> {
+        self.inner.consume_block_event(event)
+    }
+
+    pub fn consume_mempool_event(&mut self, event: MempoolEvent) {
+        self.inner.consume_mempool_event(event)
+    }
+
+    /// Insert a transaction `self` has broadcast, with an explicit `last_seen` unix timestamp, so
+    /// it becomes canonical (and shows up in
+    /// [`expected_mempool_txs`](KeychainTracker::expected_mempool_txs)) immediately instead of
+    /// waiting for the next
+    /// [`consume_mempool_event`](KeychainTracker::consume_mempool_event) round-trip.
+    pub fn insert_tx(&mut self, tx: impl Into<Arc<Transaction>>, last_seen: u64) {
+        self.inner.insert_tx(tx, last_seen)
+    }
+
+    /// Mark `txid` as evicted at `evicted_at` (unix timestamp), e.g. because `self` broadcast a
+    /// replacement for it.
+    pub fn insert_evicted_at(&mut self, txid: Txid, evicted_at: u64) {
+        self.inner.insert_evicted_at(txid, evicted_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str::FromStr;
+
+    fn test_descriptor() -> Descriptor<DescriptorPublicKey> {
+        Descriptor::from_str(
+            "tr(tpubD6NzVbkrYhZ4WZVWXBE2VDLwZXUnWCtNUv9qjgqMzKUzEwNwapDLvfAvjJ1qfS3HaBVYcjj8hXtYhosjqN3Y3vvv5McXgwjZbAgNcK6Mxpj/0/*)",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reveal_to_target_persists_last_revealed_index() {
+        let mut tracker = KeychainTracker::<&'static str>::new(
+            Network::Regtest,
+            BlockHash::from_byte_array([0; 32]),
+        );
+        tracker
+            .insert_descriptor("external", test_descriptor())
+            .unwrap();
+
+        let revealed = tracker.reveal_to_target(&"external", 3);
+        // Indices 0..=3 should have been revealed, in order.
+        assert_eq!(
+            revealed.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+
+        // Revealing again up to a lower target is a no-op: nothing new comes back.
+        assert!(tracker.reveal_to_target(&"external", 1).is_empty());
+
+        // The highest revealed index is what gets staged for persistence.
+        let changeset = tracker.take_stage();
+        assert_eq!(
+            changeset
+                .indexed_graph
+                .indexer
+                .last_revealed
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+}
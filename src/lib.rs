@@ -1,108 +1,76 @@
+mod keychain;
+mod sweep;
+mod tracker;
+
+pub use keychain::{KeychainChangeSet, KeychainTracker};
+
 use std::{collections::HashMap, sync::Arc};
 
 use bdk_bitcoind_rpc::{BlockEvent, MempoolEvent};
 use bdk_chain::{
-    CanonicalizationParams, CheckPoint, ConfirmationBlockTime, FullTxOut, IndexedTxGraph, Merge,
+    CanonicalizationParams, CheckPoint, ConfirmationBlockTime, FullTxOut, TxUpdate,
     bitcoin::{
-        Block, BlockHash, Network, ScriptBuf, Transaction,
+        Amount, Block, BlockHash, FeeRate, Network, OutPoint, ScriptBuf, Transaction, Txid,
         key::Secp256k1,
         secp256k1::{All, SecretKey},
     },
-    indexed_tx_graph,
-    local_chain::{self, LocalChain},
     miniscript::Descriptor,
+    spk_client::{FullScanRequest, FullScanRequestBuilder, SyncRequest, SyncRequestBuilder},
     spk_txout::SpkTxOutIndex,
 };
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct ChangeSet {
-    indexed_graph: indexed_tx_graph::ChangeSet<ConfirmationBlockTime, ()>,
-    local_chain: local_chain::ChangeSet,
-    network: Option<Network>,
-}
-
-impl Default for ChangeSet {
-    fn default() -> Self {
-        Self {
-            indexed_graph: Default::default(),
-            local_chain: Default::default(),
-            network: None,
-        }
-    }
-}
+use tracker::Tracker;
 
-impl Merge for ChangeSet {
-    fn merge(&mut self, other: Self) {
-        self.indexed_graph.merge(other.indexed_graph);
-        self.local_chain.merge(other.local_chain);
-        if other.network.is_some() {
-            self.network = other.network;
-        }
-    }
+/// Number of confirmations a coinbase output needs before it is spendable.
+const COINBASE_MATURITY: u32 = 100;
 
-    fn is_empty(&self) -> bool {
-        self.indexed_graph.is_empty() && self.local_chain.is_empty() && self.network.is_none()
-    }
+/// A breakdown of tracked value by spendability.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// Confirmed and spendable.
+    pub confirmed: Amount,
+    /// Unconfirmed, but trusted to confirm (e.g. change, or a predicate-approved spk).
+    pub trusted_pending: Amount,
+    /// Unconfirmed and not trusted to confirm.
+    pub untrusted_pending: Amount,
+    /// Confirmed coinbase output that has not yet reached [`COINBASE_MATURITY`].
+    pub immature: Amount,
 }
 
-impl From<local_chain::ChangeSet> for ChangeSet {
-    fn from(local_chain: local_chain::ChangeSet) -> Self {
-        Self {
-            local_chain,
-            ..Default::default()
-        }
+impl Balance {
+    /// `confirmed + trusted_pending`, i.e. value that can be spent right now.
+    pub fn spendable(&self) -> Amount {
+        self.confirmed + self.trusted_pending
     }
-}
 
-impl From<indexed_tx_graph::ChangeSet<ConfirmationBlockTime, ()>> for ChangeSet {
-    fn from(indexed_graph: indexed_tx_graph::ChangeSet<ConfirmationBlockTime, ()>) -> Self {
-        Self {
-            indexed_graph,
-            ..Default::default()
-        }
+    /// The sum of all four fields.
+    pub fn total(&self) -> Amount {
+        self.confirmed + self.trusted_pending + self.untrusted_pending + self.immature
     }
 }
 
+/// Persistable changes for a [`SpkTracker`].
+pub type ChangeSet = tracker::ChangeSet<()>;
+
 pub struct SpkTracker {
-    graph: IndexedTxGraph<ConfirmationBlockTime, SpkTxOutIndex<ScriptBuf>>,
-    chain: LocalChain,
-    stage: ChangeSet,
+    inner: Tracker<SpkTxOutIndex<ScriptBuf>>,
     secrets: HashMap<ScriptBuf, SecretKey>,
-    network: Network,
     secp: Secp256k1<All>,
 }
 
 impl SpkTracker {
     pub fn new(network: Network, genesis_hash: BlockHash) -> Self {
-        let mut stage = ChangeSet::default();
-        let graph = IndexedTxGraph::<ConfirmationBlockTime, SpkTxOutIndex<ScriptBuf>>::default();
-        let (chain, changeset) = LocalChain::from_genesis_hash(genesis_hash);
-        stage.merge(changeset.into());
         Self {
-            graph,
-            chain,
-            stage,
+            inner: Tracker::new(network, genesis_hash),
             secrets: Default::default(),
-            network,
             secp: Secp256k1::new(),
         }
     }
 
     pub fn from_changeset(changeset: ChangeSet) -> anyhow::Result<Self> {
-        let mut stage = ChangeSet::default();
-        let (graph, graph_changeset) =
-            IndexedTxGraph::<ConfirmationBlockTime, SpkTxOutIndex<ScriptBuf>>::from_changeset(
-                changeset.indexed_graph,
-                |_| anyhow::Ok(SpkTxOutIndex::<ScriptBuf>::default()),
-            )?;
-        stage.merge(graph_changeset.into());
-        let chain = LocalChain::from_changeset(changeset.local_chain)?;
         Ok(Self {
-            graph,
-            chain,
-            stage,
+            inner: Tracker::from_changeset(changeset)?,
             secrets: Default::default(),
-            network: changeset.network.ok_or(anyhow::anyhow!("no network"))?,
             secp: Secp256k1::new(),
         })
     }
@@ -111,17 +79,14 @@ impl SpkTracker {
     ///
     /// For persistence.
     pub fn take_stage(&mut self) -> ChangeSet {
-        core::mem::take(&mut self.stage)
+        self.inner.take_stage()
     }
 
     /// Reindex.
     ///
     /// Incase an spk was added after a relevant transaction was already synced.
     pub fn reindex(&mut self) -> bool {
-        let changeset = self.graph.reindex();
-        let has_changes = !changeset.is_empty();
-        self.stage.merge(changeset.into());
-        has_changes
+        self.inner.reindex()
     }
 }
 
@@ -136,7 +101,7 @@ impl SpkTracker {
     pub fn add_secret(&mut self, secret: SecretKey) -> anyhow::Result<bool> {
         let (pk, _) = secret.x_only_public_key(&self.secp);
         let spk = Descriptor::new_tr(pk, None)?.script_pubkey();
-        if self.graph.index.insert_spk(spk.clone(), spk.clone()) {
+        if self.inner.graph.index.insert_spk(spk.clone(), spk.clone()) {
             self.secrets.insert(spk, secret);
             return Ok(true);
         }
@@ -147,48 +112,346 @@ impl SpkTracker {
         &self.secrets
     }
 
+    /// The `secp256k1` context used to derive spks and sign spends.
+    pub(crate) fn secp(&self) -> &Secp256k1<All> {
+        &self.secp
+    }
+
     /// Canonical UTXOs
     pub fn utxos(&self) -> impl Iterator<Item = (ScriptBuf, FullTxOut<ConfirmationBlockTime>)> {
-        self.graph.graph().filter_chain_unspents(
-            &self.chain,
-            self.chain.tip().block_id(),
+        self.inner.graph.filter_chain_unspents(
+            &self.inner.chain,
+            self.inner.chain.tip().block_id(),
             CanonicalizationParams::default(),
-            self.graph.index.outpoints().clone(),
+            self.inner.graph.index.outpoints().clone(),
         )
     }
+
+    /// Summarize [`utxos`](SpkTracker::utxos) by spendability.
+    ///
+    /// Every unconfirmed UTXO is treated as trusted-pending, since every tracked spk is a
+    /// single-key taproot output owned by `self`. Use [`SpkTracker::balance_with`] if some spks
+    /// should not be trusted (e.g. externally-supplied addresses).
+    pub fn balance(&self) -> Balance {
+        self.balance_with(|_| true)
+    }
+
+    /// Like [`SpkTracker::balance`], but `is_trusted` decides whether an unconfirmed UTXO at a
+    /// given spk counts as `trusted_pending` or `untrusted_pending`.
+    pub fn balance_with(&self, is_trusted: impl Fn(&ScriptBuf) -> bool) -> Balance {
+        let tip_height = self.inner.chain.tip().height();
+        let mut balance = Balance::default();
+        for (spk, utxo) in self.utxos() {
+            if utxo.is_on_coinbase {
+                let confirmed_height = match utxo.chain_position.confirmation_height_upper_bound()
+                {
+                    Some(height) => height,
+                    None => continue, // a coinbase output cannot be unconfirmed
+                };
+                // `+ 1` because confirmations = tip_height - confirmed_height + 1: a coinbase
+                // confirmed in the tip block already has 1 confirmation, not 0.
+                if tip_height.saturating_sub(confirmed_height) + 1 >= COINBASE_MATURITY {
+                    balance.confirmed += utxo.txout.value;
+                } else {
+                    balance.immature += utxo.txout.value;
+                }
+                continue;
+            }
+            if utxo.chain_position.is_confirmed() {
+                balance.confirmed += utxo.txout.value;
+            } else if is_trusted(&spk) {
+                balance.trusted_pending += utxo.txout.value;
+            } else {
+                balance.untrusted_pending += utxo.txout.value;
+            }
+        }
+        balance
+    }
+}
+
+/// Methods for building and signing spends.
+impl SpkTracker {
+    /// Build and key-path-sign a transaction spending tracked UTXOs to `recipients`.
+    ///
+    /// Inputs are selected from [`SpkTracker::utxos`] and any leftover value is sent to
+    /// `change_spk`, unless it would be dust in which case it is dropped into fees.
+    ///
+    /// Returns the finalized, fully-signed [`Transaction`] ready to broadcast, plus the change
+    /// [`OutPoint`] if a change output was created.
+    pub fn build_sweep_tx(
+        &self,
+        recipients: &[(ScriptBuf, Amount)],
+        change_spk: ScriptBuf,
+        feerate: FeeRate,
+    ) -> anyhow::Result<(Transaction, Option<OutPoint>)> {
+        sweep::build_and_sign(self, recipients, change_spk, feerate)
+    }
 }
 
 /// Methods for syncing with `bdk_bitcoind_rpc`.
 impl SpkTracker {
     pub fn tip(&self) -> CheckPoint {
-        self.chain.tip()
+        self.inner.tip()
     }
 
     pub fn expected_mempool_txs(&self) -> impl Iterator<Item = Arc<Transaction>> {
-        self.graph
-            .graph()
-            .list_canonical_txs(&self.chain, self.chain.tip().block_id(), Default::default())
-            .filter(|c_tx| c_tx.chain_position.is_unconfirmed())
-            .map(|c_tx| c_tx.tx_node.tx)
+        self.inner.expected_mempool_txs()
     }
 
     pub fn consume_block_event(&mut self, event: BlockEvent<Block>) -> anyhow::Result<()> {
-        let changeset = self
-            .graph
-            .apply_block_relevant(&event.block, event.block_height());
-        self.stage.merge(changeset.into());
-        let changeset = self.chain.apply_update(event.checkpoint)?;
-        self.stage.merge(changeset.into());
-        Ok(())
+        self.inner.consume_block_event(event)
     }
 
     pub fn consume_mempool_event(&mut self, event: MempoolEvent) {
-        let changeset = self.graph.batch_insert_relevant_unconfirmed(event.update);
-        self.stage.merge(changeset.into());
-        let changeset = self.graph.batch_insert_relevant_evicted_at(event.evicted);
-        self.stage.merge(changeset.into());
+        self.inner.consume_mempool_event(event)
+    }
+
+    /// Insert a transaction `self` has broadcast, with an explicit `last_seen` unix timestamp, so
+    /// it becomes canonical (and shows up in [`SpkTracker::expected_mempool_txs`]) immediately
+    /// instead of waiting for the next [`consume_mempool_event`](SpkTracker::consume_mempool_event)
+    /// round-trip.
+    pub fn insert_tx(&mut self, tx: impl Into<Arc<Transaction>>, last_seen: u64) {
+        self.inner.insert_tx(tx, last_seen)
+    }
+
+    /// Mark `txid` as evicted at `evicted_at` (unix timestamp), e.g. because `self` broadcast a
+    /// replacement for it.
+    pub fn insert_evicted_at(&mut self, txid: Txid, evicted_at: u64) {
+        self.inner.insert_evicted_at(txid, evicted_at)
+    }
+}
+
+/// Methods for syncing with `bdk_electrum`/`bdk_esplora`.
+impl SpkTracker {
+    /// Start a [`SyncRequest`] for every spk currently tracked by `self`.
+    ///
+    /// The request starts from [`SpkTracker::tip`] and is pre-populated with the txs already in
+    /// `self.graph`, so the client only needs to fetch what it doesn't already have.
+    pub fn start_sync(&self) -> SyncRequestBuilder<ScriptBuf> {
+        SyncRequest::builder()
+            .chain_tip(self.inner.chain.tip())
+            .spks_with_indexes(
+                self.inner
+                    .graph
+                    .index
+                    .all_spks()
+                    .iter()
+                    .map(|(i, spk)| (i.clone(), spk.clone())),
+            )
+            .cache_graph_txs(&self.inner.graph)
+    }
+
+    /// Start a [`FullScanRequest`] for every spk currently tracked by `self`.
+    ///
+    /// Like [`SpkTracker::start_sync`], this is pre-populated with the txs already in
+    /// `self.graph` to avoid refetching transactions the client has already seen. Since
+    /// `self.graph.index` is not keychain-based yet, all spks are reported under a single `()`
+    /// keychain with no stop-gap (there is no "next" spk to derive).
+    pub fn start_full_scan(&self) -> FullScanRequestBuilder<()> {
+        FullScanRequest::builder()
+            .chain_tip(self.inner.chain.tip())
+            .spks_for_keychain(
+                (),
+                self.inner
+                    .graph
+                    .index
+                    .all_spks()
+                    .values()
+                    .cloned()
+                    .enumerate()
+                    .map(|(i, spk)| (i as u32, spk)),
+            )
+            .cache_graph_txs(&self.inner.graph)
+    }
+
+    /// Fold a [`TxUpdate`] and new `tip` (as returned by `bdk_electrum`/`bdk_esplora`) back into
+    /// `self.graph`/`self.chain`, staging the resulting changeset.
+    pub fn apply_update(
+        &mut self,
+        update: TxUpdate<ConfirmationBlockTime>,
+        tip: CheckPoint,
+    ) -> anyhow::Result<()> {
+        self.inner.apply_update(update, tip)
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use bdk_chain::{
+        BlockId,
+        bitcoin::{
+            Sequence, TxIn, TxOut, Witness, absolute::LockTime, hashes::Hash, transaction::Version,
+        },
+    };
+
+    fn block_id(height: u32) -> BlockId {
+        BlockId {
+            height,
+            hash: BlockHash::from_byte_array([height as u8; 32]),
+        }
+    }
+
+    /// Extend `tracker`'s chain up to and including `height`, insert `tx` into the graph, and
+    /// anchor it as confirmed at `height`.
+    fn confirm_at(tracker: &mut SpkTracker, tx: Transaction, height: u32) {
+        let txid = tx.compute_txid();
+        let _ = tracker.inner.graph.insert_tx(Arc::new(tx));
+        let _ = tracker.inner.chain.insert_block(block_id(height)).unwrap();
+        let _ = tracker.inner.graph.insert_anchor(
+            txid,
+            ConfirmationBlockTime {
+                block_id: block_id(height),
+                confirmation_time: 0,
+            },
+        );
+    }
+
+    /// Add a secret to `tracker` and return the spk it derives, without relying on
+    /// `HashMap` iteration order to recover it.
+    fn add_secret_spk(tracker: &mut SpkTracker, byte: u8) -> ScriptBuf {
+        let secret = SecretKey::from_slice(&[byte; 32]).unwrap();
+        let (pk, _) = secret.x_only_public_key(tracker.secp());
+        let spk = Descriptor::new_tr(pk, None).unwrap().script_pubkey();
+        tracker.add_secret(secret).unwrap();
+        spk
+    }
+
+    fn tx_paying(spk: ScriptBuf, value: Amount, input_seed: u8, is_coinbase: bool) -> Transaction {
+        let previous_output = if is_coinbase {
+            OutPoint::null()
+        } else {
+            OutPoint::new(Txid::from_byte_array([input_seed; 32]), 0)
+        };
+        Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: spk,
+            }],
+        }
+    }
+
+    #[test]
+    fn coin_selection_prefers_highest_effective_value_first() {
+        let mut tracker = SpkTracker::new(Network::Regtest, BlockHash::from_byte_array([0; 32]));
+
+        let spks = (0..4u8)
+            .map(|i| add_secret_spk(&mut tracker, i + 1))
+            .collect::<Vec<_>>();
+
+        // One big UTXO and three small (1,000-sat) UTXOs, all confirmed at the same height.
+        confirm_at(
+            &mut tracker,
+            tx_paying(spks[0].clone(), Amount::from_sat(1_000_000), 10, false),
+            1,
+        );
+        for (i, spk) in spks[1..].iter().enumerate() {
+            confirm_at(
+                &mut tracker,
+                tx_paying(spk.clone(), Amount::from_sat(1_000), 20 + i as u8, false),
+                1,
+            );
+        }
+
+        let change_spk = spks[0].clone();
+        let (tx, _change_outpoint) = tracker
+            .build_sweep_tx(
+                &[(spks[0].clone(), Amount::from_sat(5_000))],
+                change_spk,
+                FeeRate::from_sat_per_vb_unchecked(1),
+            )
+            .unwrap();
+
+        // The single 1,000,000-sat UTXO alone covers the send; none of the small UTXOs should
+        // have been selected.
+        assert_eq!(tx.input.len(), 1);
+    }
+
+    #[test]
+    fn coinbase_matures_at_exactly_100_confirmations() {
+        let mut tracker = SpkTracker::new(Network::Regtest, BlockHash::from_byte_array([0; 32]));
+        let secret = SecretKey::from_slice(&[1; 32]).unwrap();
+        tracker.add_secret(secret).unwrap();
+        let spk = tracker.secrets_by_spk().keys().next().unwrap().clone();
+
+        confirm_at(
+            &mut tracker,
+            tx_paying(spk, Amount::from_sat(50_00000000), 0, true),
+            1,
+        );
+
+        // Confirmed at height 1 with tip at height 99: 99 confirmations, still immature.
+        let _ = tracker.inner.chain.insert_block(block_id(99)).unwrap();
+        let balance = tracker.balance();
+        assert_eq!(balance.confirmed, Amount::ZERO);
+        assert_eq!(balance.immature, Amount::from_sat(50_00000000));
+
+        // Tip at height 100: exactly 100 confirmations, now mature.
+        let _ = tracker.inner.chain.insert_block(block_id(100)).unwrap();
+        let balance = tracker.balance();
+        assert_eq!(balance.confirmed, Amount::from_sat(50_00000000));
+        assert_eq!(balance.immature, Amount::ZERO);
+    }
+
+    #[test]
+    fn start_sync_caches_known_txids_so_client_does_not_refetch_them() {
+        let mut tracker = SpkTracker::new(Network::Regtest, BlockHash::from_byte_array([0; 32]));
+        let spk = add_secret_spk(&mut tracker, 1);
+        let tx = tx_paying(spk, Amount::from_sat(10_000), 5, false);
+        let txid = tx.compute_txid();
+        confirm_at(&mut tracker, tx, 1);
+
+        let request = tracker.start_sync().build();
+        // The tx was already inserted into the graph above, so `cache_graph_txs` should have
+        // pre-populated the request with it instead of leaving the client to refetch it.
+        assert!(request.txs().any(|tx| tx.compute_txid() == txid));
+    }
+
+    #[test]
+    fn utxos_returns_tracked_outputs_after_insert() {
+        let mut tracker = SpkTracker::new(Network::Regtest, BlockHash::from_byte_array([0; 32]));
+        let spk = add_secret_spk(&mut tracker, 1);
+        confirm_at(
+            &mut tracker,
+            tx_paying(spk.clone(), Amount::from_sat(20_000), 1, false),
+            1,
+        );
+
+        // The migrated `TxGraph<A, SpkTxOutIndex<ScriptBuf>>` storage should still recognize the
+        // output as belonging to a tracked spk and surface it as a UTXO.
+        let utxos = tracker.utxos().collect::<Vec<_>>();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].0, spk);
+        assert_eq!(utxos[0].1.txout.value, Amount::from_sat(20_000));
+    }
+
+    #[test]
+    fn insert_tx_and_insert_evicted_at_flip_mempool_visibility() {
+        let mut tracker = SpkTracker::new(Network::Regtest, BlockHash::from_byte_array([0; 32]));
+        let spk = add_secret_spk(&mut tracker, 1);
+        let tx = tx_paying(spk, Amount::from_sat(15_000), 2, false);
+        let txid = tx.compute_txid();
+
+        tracker.insert_tx(tx, 1);
+        assert!(
+            tracker
+                .expected_mempool_txs()
+                .any(|tx| tx.compute_txid() == txid)
+        );
+
+        tracker.insert_evicted_at(txid, 2);
+        assert!(
+            !tracker
+                .expected_mempool_txs()
+                .any(|tx| tx.compute_txid() == txid)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Transaction building and key-path signing for sweeping tracked taproot UTXOs.
+
+use bdk_chain::{
+    ConfirmationBlockTime,
+    bitcoin::{
+        Amount, FeeRate, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Weight, Witness,
+        absolute::LockTime,
+        key::{Keypair, TapTweak},
+        secp256k1::Message,
+        sighash::{Prevouts, SighashCache, TapSighashType},
+        transaction::Version,
+    },
+};
+
+use crate::SpkTracker;
+
+/// Weight of a single key-path-spend taproot input (fixed 57.5 vbytes: 41 non-witness vbytes
+/// plus a 66-byte witness stack, item count + length + 64-byte signature, counted at 1/4 weight).
+const TR_KEYSPEND_INPUT_WEIGHT: Weight = Weight::from_wu(230);
+
+/// Weight of the fixed per-transaction overhead (version, locktime, segwit marker/flag, and the
+/// input/output count varints).
+const TX_FIXED_WEIGHT: Weight = Weight::from_wu(42);
+
+/// Dust limit for a taproot output, in sats (value below which an output cannot be relayed).
+const TR_DUST_LIMIT: Amount = Amount::from_sat(330);
+
+/// A UTXO candidate for coin selection.
+struct Candidate {
+    spk: ScriptBuf,
+    utxo: bdk_chain::FullTxOut<ConfirmationBlockTime>,
+    /// `value - (feerate * TR_KEYSPEND_INPUT_WEIGHT)`, i.e. what this input contributes to the
+    /// transaction once its own fee is paid for.
+    effective_value: i64,
+}
+
+/// Build and key-path-sign a transaction spending `tracker`'s tracked UTXOs to `recipients`.
+///
+/// Inputs are selected greedily by [`Candidate::effective_value`] (highest first), skipping any
+/// UTXO that would cost more in fees than it contributes. Selection stops as soon as the selected
+/// inputs cover `recipients` and fees. If the leftover is below [`TR_DUST_LIMIT`] (or would fall
+/// below it after paying for the change output's own weight), it is dropped into fees; otherwise
+/// it goes to a change output at `change_spk`.
+///
+/// This is a simple greedy selector, not a branch-and-bound/waste-minimizing search like
+/// `bdk_coin_select`'s: it takes the shortest covering prefix of a single
+/// highest-effective-value-first pass, and does not look for an input set that avoids change or
+/// for the lowest-waste combination of inputs.
+pub fn build_and_sign(
+    tracker: &SpkTracker,
+    recipients: &[(ScriptBuf, Amount)],
+    change_spk: ScriptBuf,
+    feerate: FeeRate,
+) -> anyhow::Result<(Transaction, Option<OutPoint>)> {
+    let recipients_value = recipients
+        .iter()
+        .try_fold(Amount::ZERO, |acc, (_, amount)| acc.checked_add(*amount))
+        .ok_or_else(|| anyhow::anyhow!("recipient amounts overflow"))?;
+
+    let input_fee = feerate
+        .fee_wu(TR_KEYSPEND_INPUT_WEIGHT)
+        .ok_or_else(|| anyhow::anyhow!("feerate overflow"))?
+        .to_sat() as i64;
+
+    let mut candidates = tracker
+        .utxos()
+        .map(|(spk, utxo)| Candidate {
+            effective_value: utxo.txout.value.to_sat() as i64 - input_fee,
+            spk,
+            utxo,
+        })
+        .filter(|c| c.effective_value > 0)
+        .collect::<Vec<_>>();
+    // Ascending, so `pop()` below pulls the highest effective-value candidate first.
+    candidates.sort_unstable_by_key(|c| c.effective_value);
+
+    let base_output_weight = recipients
+        .iter()
+        .map(|(spk, amount)| {
+            TxOut {
+                value: *amount,
+                script_pubkey: spk.clone(),
+            }
+            .weight()
+        })
+        .try_fold(Weight::ZERO, |acc, w| {
+            acc.checked_add(w)
+                .ok_or_else(|| anyhow::anyhow!("output weight overflow"))
+        })?;
+
+    // Select inputs greedily (highest effective value first) until recipients and fees are
+    // covered, tracking the transaction's weight/fee along the way assuming no change output.
+    let mut selected = Vec::<Candidate>::new();
+    let mut selected_value = Amount::ZERO;
+    let (tx_weight_without_change, fee_without_change) = loop {
+        let weight = TX_FIXED_WEIGHT
+            .checked_add(base_output_weight)
+            .and_then(|w| w.checked_add(TR_KEYSPEND_INPUT_WEIGHT * selected.len() as u64))
+            .ok_or_else(|| anyhow::anyhow!("transaction weight overflow"))?;
+        let fee = feerate
+            .fee_wu(weight)
+            .ok_or_else(|| anyhow::anyhow!("feerate overflow"))?;
+        if selected_value >= recipients_value + fee {
+            break (weight, fee);
+        }
+        let next = candidates.pop().ok_or_else(|| {
+            anyhow::anyhow!("insufficient funds: cannot cover recipients and fees")
+        })?;
+        selected_value += next.utxo.txout.value;
+        selected.push(next);
+    };
+
+    // Changeless solution: the surplus left over after paying recipients and fees.
+    let leftover = selected_value - recipients_value - fee_without_change;
+
+    let change_value = if leftover < TR_DUST_LIMIT {
+        None
+    } else {
+        let change_weight = TxOut {
+            value: leftover,
+            script_pubkey: change_spk.clone(),
+        }
+        .weight();
+        // Re-derive the fee now that a change output is in the mix; if the change output would
+        // not survive paying for its own extra weight, drop it into fees instead.
+        let fee_with_change = feerate
+            .fee_wu(tx_weight_without_change + change_weight)
+            .ok_or_else(|| anyhow::anyhow!("feerate overflow"))?;
+        selected_value
+            .checked_sub(recipients_value)
+            .and_then(|v| v.checked_sub(fee_with_change))
+            .filter(|value| *value >= TR_DUST_LIMIT)
+    };
+
+    let mut outputs = recipients
+        .iter()
+        .map(|(spk, amount)| TxOut {
+            value: *amount,
+            script_pubkey: spk.clone(),
+        })
+        .collect::<Vec<_>>();
+    let change_vout = change_value.map(|value| {
+        outputs.push(TxOut {
+            value,
+            script_pubkey: change_spk,
+        });
+        outputs.len() - 1
+    });
+
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: selected
+            .iter()
+            .map(|c| TxIn {
+                previous_output: c.utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    };
+
+    let prevouts = selected
+        .iter()
+        .map(|c| c.utxo.txout.clone())
+        .collect::<Vec<_>>();
+    let unsigned_tx = tx.clone();
+    let mut sighash_cache = SighashCache::new(&unsigned_tx);
+    let mut signatures = Vec::with_capacity(selected.len());
+    for (i, candidate) in selected.iter().enumerate() {
+        let secret = tracker
+            .secrets_by_spk()
+            .get(&candidate.spk)
+            .ok_or_else(|| anyhow::anyhow!("missing secret for tracked spk"))?;
+        let sighash = sighash_cache.taproot_key_spend_signature_hash(
+            i,
+            &Prevouts::All(&prevouts),
+            TapSighashType::Default,
+        )?;
+        let keypair =
+            Keypair::from_secret_key(tracker.secp(), secret).tap_tweak(tracker.secp(), None);
+        let message = Message::from_digest(sighash.to_byte_array());
+        let signature = tracker.secp().sign_schnorr(&message, &keypair.to_inner());
+        signatures.push(bdk_chain::bitcoin::taproot::Signature {
+            signature,
+            sighash_type: TapSighashType::Default,
+        });
+    }
+    for (input, signature) in tx.input.iter_mut().zip(signatures) {
+        input.witness = Witness::p2tr_key_spend(&signature);
+    }
+
+    let change_outpoint = change_vout.map(|vout| OutPoint {
+        txid: tx.compute_txid(),
+        vout: vout as u32,
+    });
+    Ok((tx, change_outpoint))
+}
@@ -0,0 +1,195 @@
+//! Generic tracker plumbing shared by [`SpkTracker`](crate::SpkTracker) and
+//! [`KeychainTracker`](crate::keychain::KeychainTracker).
+//!
+//! Both trackers are a [`TxGraph`] of canonical chain state paired with a [`LocalChain`] tip; the
+//! only thing that differs between them is the `Indexer` (`X`) used to recognize which spks are
+//! ours. [`Tracker<X>`] holds that common state once, so the sync/chain-event plumbing below is
+//! implemented a single time and reused by both.
+
+use std::sync::Arc;
+
+use bdk_bitcoind_rpc::{BlockEvent, MempoolEvent};
+use bdk_chain::{
+    CheckPoint, ConfirmationBlockTime, Indexer, Merge, TxGraph, TxUpdate,
+    bitcoin::{Block, BlockHash, Network, Transaction, Txid},
+    local_chain::{self, LocalChain},
+    tx_graph,
+};
+
+/// Persistable changes for a [`Tracker`], generic over the indexer's own changeset type `IA`.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(bound = "IA: serde::Serialize + serde::de::DeserializeOwned")]
+pub struct ChangeSet<IA> {
+    pub(crate) indexed_graph: tx_graph::ChangeSet<ConfirmationBlockTime, IA>,
+    pub(crate) local_chain: local_chain::ChangeSet,
+    pub(crate) network: Option<Network>,
+}
+
+impl<IA: Default> Default for ChangeSet<IA> {
+    fn default() -> Self {
+        Self {
+            indexed_graph: Default::default(),
+            local_chain: Default::default(),
+            network: None,
+        }
+    }
+}
+
+impl<IA: Merge> Merge for ChangeSet<IA> {
+    fn merge(&mut self, other: Self) {
+        self.indexed_graph.merge(other.indexed_graph);
+        self.local_chain.merge(other.local_chain);
+        if other.network.is_some() {
+            self.network = other.network;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.indexed_graph.is_empty() && self.local_chain.is_empty() && self.network.is_none()
+    }
+}
+
+impl<IA: Default> From<local_chain::ChangeSet> for ChangeSet<IA> {
+    fn from(local_chain: local_chain::ChangeSet) -> Self {
+        Self {
+            local_chain,
+            ..Default::default()
+        }
+    }
+}
+
+impl<IA: Default> From<tx_graph::ChangeSet<ConfirmationBlockTime, IA>> for ChangeSet<IA> {
+    fn from(indexed_graph: tx_graph::ChangeSet<ConfirmationBlockTime, IA>) -> Self {
+        Self {
+            indexed_graph,
+            ..Default::default()
+        }
+    }
+}
+
+/// Shared tracker state: a [`TxGraph`] indexed by `X`, kept in sync with a [`LocalChain`] tip.
+///
+/// [`SpkTracker`](crate::SpkTracker) and [`KeychainTracker`](crate::keychain::KeychainTracker) are
+/// thin wrappers around this, each adding only what's specific to its own indexer (derived
+/// secrets for the former, descriptors/gap-limit revelation for the latter).
+pub(crate) struct Tracker<X: Indexer> {
+    pub(crate) graph: TxGraph<ConfirmationBlockTime, X>,
+    pub(crate) chain: LocalChain,
+    pub(crate) stage: ChangeSet<X::ChangeSet>,
+    pub(crate) network: Network,
+}
+
+impl<X: Indexer + Default> Tracker<X> {
+    pub(crate) fn new(network: Network, genesis_hash: BlockHash) -> Self {
+        let mut stage = ChangeSet::default();
+        let graph = TxGraph::<ConfirmationBlockTime, X>::default();
+        let (chain, changeset) = LocalChain::from_genesis_hash(genesis_hash);
+        stage.merge(changeset.into());
+        Self {
+            graph,
+            chain,
+            stage,
+            network,
+        }
+    }
+
+    pub(crate) fn from_changeset(changeset: ChangeSet<X::ChangeSet>) -> anyhow::Result<Self> {
+        let mut stage = ChangeSet::default();
+        let (graph, graph_changeset) =
+            TxGraph::<ConfirmationBlockTime, X>::from_changeset(changeset.indexed_graph, |_| {
+                anyhow::Ok(X::default())
+            })?;
+        stage.merge(graph_changeset.into());
+        let chain = LocalChain::from_changeset(changeset.local_chain)?;
+        Ok(Self {
+            graph,
+            chain,
+            stage,
+            network: changeset.network.ok_or(anyhow::anyhow!("no network"))?,
+        })
+    }
+}
+
+/// Methods that don't depend on how `X` derives its spks, so they're implemented once here and
+/// reused by both trackers instead of per-tracker.
+impl<X: Indexer> Tracker<X> {
+    /// Take from the staged changes.
+    ///
+    /// For persistence.
+    pub(crate) fn take_stage(&mut self) -> ChangeSet<X::ChangeSet> {
+        core::mem::take(&mut self.stage)
+    }
+
+    /// Reindex, delegating to the graph's indexer reindex, which re-scans already-stored txs
+    /// against the current `index` state.
+    ///
+    /// Incase a new spk/descriptor was added after a relevant transaction was already synced.
+    pub(crate) fn reindex(&mut self) -> bool {
+        let changeset = self.graph.reindex();
+        let has_changes = !changeset.is_empty();
+        self.stage.merge(changeset.into());
+        has_changes
+    }
+
+    pub(crate) fn tip(&self) -> CheckPoint {
+        self.chain.tip()
+    }
+
+    pub(crate) fn expected_mempool_txs(&self) -> impl Iterator<Item = Arc<Transaction>> {
+        self.graph
+            .list_canonical_txs(&self.chain, self.chain.tip().block_id(), Default::default())
+            .filter(|c_tx| c_tx.chain_position.is_unconfirmed())
+            .map(|c_tx| c_tx.tx_node.tx)
+    }
+
+    pub(crate) fn consume_block_event(&mut self, event: BlockEvent<Block>) -> anyhow::Result<()> {
+        let changeset = self
+            .graph
+            .apply_block_relevant(&event.block, event.block_height());
+        self.stage.merge(changeset.into());
+        let changeset = self.chain.apply_update(event.checkpoint)?;
+        self.stage.merge(changeset.into());
+        Ok(())
+    }
+
+    pub(crate) fn consume_mempool_event(&mut self, event: MempoolEvent) {
+        let changeset = self.graph.batch_insert_relevant_unconfirmed(event.update);
+        self.stage.merge(changeset.into());
+        let changeset = self.graph.batch_insert_relevant_evicted_at(event.evicted);
+        self.stage.merge(changeset.into());
+    }
+
+    /// Insert a transaction `self` has broadcast, with an explicit `last_seen` unix timestamp, so
+    /// it becomes canonical (and shows up in [`expected_mempool_txs`](Tracker::expected_mempool_txs))
+    /// immediately instead of waiting for the next
+    /// [`consume_mempool_event`](Tracker::consume_mempool_event) round-trip.
+    pub(crate) fn insert_tx(&mut self, tx: impl Into<Arc<Transaction>>, last_seen: u64) {
+        let tx = tx.into();
+        let txid = tx.compute_txid();
+        let changeset = self.graph.insert_tx(tx);
+        self.stage.merge(changeset.into());
+        let changeset = self.graph.insert_seen_at(txid, last_seen);
+        self.stage.merge(changeset.into());
+    }
+
+    /// Mark `txid` as evicted at `evicted_at` (unix timestamp), e.g. because `self` broadcast a
+    /// replacement for it.
+    pub(crate) fn insert_evicted_at(&mut self, txid: Txid, evicted_at: u64) {
+        let changeset = self.graph.insert_evicted_at(txid, evicted_at);
+        self.stage.merge(changeset.into());
+    }
+
+    /// Fold a [`TxUpdate`] and new `tip` (as returned by `bdk_electrum`/`bdk_esplora`) back into
+    /// `self.graph`/`self.chain`, staging the resulting changeset.
+    pub(crate) fn apply_update(
+        &mut self,
+        update: TxUpdate<ConfirmationBlockTime>,
+        tip: CheckPoint,
+    ) -> anyhow::Result<()> {
+        let changeset = self.graph.apply_update(update);
+        self.stage.merge(changeset.into());
+        let changeset = self.chain.apply_update(tip)?;
+        self.stage.merge(changeset.into());
+        Ok(())
+    }
+}